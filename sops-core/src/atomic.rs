@@ -0,0 +1,183 @@
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::{fs, process};
+
+/// Disambiguates concurrent `atomic_write` calls to the same destination
+/// within this process: the PID alone collides between them since they'd
+/// otherwise produce an identical temp path.
+static TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+fn unique_tmp_suffix() -> String {
+    format!("{}-{}", process::id(), TMP_COUNTER.fetch_add(1, Ordering::Relaxed))
+}
+
+/// Returns `true` if `err` is the OS's "invalid cross-device link" error,
+/// i.e. `from` and `to` live on different filesystems and can't be renamed.
+fn is_cross_device(err: &io::Error) -> bool {
+    #[cfg(unix)]
+    {
+        err.raw_os_error() == Some(18) // EXDEV
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = err;
+        false
+    }
+}
+
+/// Renames `from` onto `to`, falling back to copy+remove when they're on
+/// different filesystems (where `rename` can't be atomic anyway).
+fn atomic_rename(from: &Path, to: &Path) -> io::Result<()> {
+    match fs::rename(from, to) {
+        Ok(()) => Ok(()),
+        Err(e) if is_cross_device(&e) => {
+            fs::copy(from, to)?;
+            fs::remove_file(from)?;
+            Ok(())
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Applies `path`'s current permissions to `tmp_path`, or `0600` if `path`
+/// doesn't exist yet (these are secrets files, so a brand-new one should
+/// default to owner-only rather than whatever the umask happens to allow).
+/// A no-op on platforms without a Unix-style mode bit concept.
+fn preserve_permissions(tmp_path: &Path, path: &Path) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let perms = match fs::metadata(path) {
+            Ok(meta) => meta.permissions(),
+            Err(_) => fs::Permissions::from_mode(0o600),
+        };
+        fs::set_permissions(tmp_path, perms)
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (tmp_path, path);
+        Ok(())
+    }
+}
+
+/// Writes `bytes` to `path` without ever leaving a truncated file in place:
+/// the data is written and synced to a sibling temp file first, then moved
+/// onto `path` with a single rename so readers only ever see the old or the
+/// fully-written new contents, never a partial write. The temp file is given
+/// `path`'s current permissions before the rename, so a plaintext secrets
+/// file never gets silently widened to the default (world-readable) mode a
+/// freshly created file would otherwise get.
+pub fn atomic_write(path: &Path, bytes: &[u8]) -> io::Result<()> {
+    let dir = path.parent().unwrap_or_else(|| Path::new("."));
+    let file_name = path.file_name().and_then(|n| n.to_str()).unwrap_or("sops-tmp");
+    let tmp_path = dir.join(format!("{}.tmp-{}", file_name, unique_tmp_suffix()));
+
+    let mut tmp_file = fs::File::create(&tmp_path)?;
+    tmp_file.write_all(bytes)?;
+    tmp_file.sync_all()?;
+    drop(tmp_file);
+
+    preserve_permissions(&tmp_path, path)?;
+
+    atomic_rename(&tmp_path, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn scratch_dir(tag: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("sops-core-test-{}-{}", tag, process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn atomic_write_creates_file_with_contents() {
+        let dir = scratch_dir("write-create");
+        let target = dir.join("secret.yaml");
+
+        atomic_write(&target, b"hello").unwrap();
+
+        assert_eq!(fs::read(&target).unwrap(), b"hello");
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn concurrent_atomic_writes_to_the_same_destination_do_not_collide() {
+        let dir = scratch_dir("write-concurrent");
+        let target = dir.join("secret.yaml");
+
+        // Two in-process writers racing to the same destination must get
+        // distinct temp paths, not just distinct PIDs (they share one).
+        let writers: Vec<_> = (0..8)
+            .map(|i| {
+                let target = target.clone();
+                std::thread::spawn(move || atomic_write(&target, format!("payload-{}", i).as_bytes()))
+            })
+            .collect();
+
+        for writer in writers {
+            writer.join().unwrap().unwrap();
+        }
+
+        let leftovers: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_name() != "secret.yaml")
+            .collect();
+        assert!(leftovers.is_empty(), "unexpected leftover files: {:?}", leftovers);
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[test]
+    fn atomic_write_leaves_no_tmp_file_behind() {
+        let dir = scratch_dir("write-no-tmp");
+        let target = dir.join("secret.yaml");
+
+        atomic_write(&target, b"first").unwrap();
+        atomic_write(&target, b"second").unwrap();
+
+        let leftovers: Vec<_> = fs::read_dir(&dir)
+            .unwrap()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_name() != "secret.yaml")
+            .collect();
+        assert!(leftovers.is_empty(), "unexpected leftover files: {:?}", leftovers);
+        assert_eq!(fs::read(&target).unwrap(), b"second");
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn atomic_write_preserves_destination_permissions() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = scratch_dir("write-preserve-perms");
+        let target = dir.join("secret.yaml");
+        fs::write(&target, b"original").unwrap();
+        fs::set_permissions(&target, fs::Permissions::from_mode(0o600)).unwrap();
+
+        atomic_write(&target, b"rotated").unwrap();
+
+        let mode = fs::metadata(&target).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode, 0o600, "atomic_write widened the destination's permissions");
+        fs::remove_dir_all(&dir).ok();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn atomic_write_defaults_new_file_to_owner_only() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = scratch_dir("write-new-file-perms");
+        let target = dir.join("secret.yaml");
+
+        atomic_write(&target, b"fresh").unwrap();
+
+        let mode = fs::metadata(&target).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode, 0o600);
+        fs::remove_dir_all(&dir).ok();
+    }
+}
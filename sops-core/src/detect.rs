@@ -0,0 +1,48 @@
+use std::path::Path;
+
+/// Returns `true` if `content` looks like a SOPS-encrypted file: it must
+/// carry the `sops:` metadata block together with either the `encrypted_*`
+/// key list or at least one `ENC[...]` value.
+pub fn is_sops_encrypted(content: &str) -> bool {
+    content.contains("sops:") && (content.contains("encrypted_") || content.contains("ENC["))
+}
+
+/// Returns `true` if `path`'s file name matches one of the conventional
+/// SOPS-managed extensions. This is a fallback used when a file isn't
+/// covered by an explicit `.sops.yaml` `creation_rule` (or there's no
+/// `.sops.yaml` at all) and content sniffing alone isn't conclusive.
+pub fn detect_by_extension(path: &Path) -> bool {
+    let Some(file_name) = path.file_name().and_then(|n| n.to_str()) else {
+        return false;
+    };
+
+    file_name.ends_with(".sops.yaml")
+        || file_name.ends_with(".sops.json")
+        || file_name.ends_with(".enc.yaml")
+        || file_name.ends_with(".enc.json")
+        || file_name.ends_with(".sops")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    #[test]
+    fn encrypted_requires_both_sops_header_and_enc_marker() {
+        assert!(is_sops_encrypted("sops:\n    lastmodified: x\nkey: ENC[AES256_GCM,data:xx]"));
+        assert!(is_sops_encrypted("sops:\n    encrypted_regex: .*\nkey: plain"));
+        assert!(!is_sops_encrypted("key: ENC[AES256_GCM,data:xx]")); // no sops: header
+        assert!(!is_sops_encrypted("sops:\n    lastmodified: x\nkey: plain")); // no ciphertext marker
+    }
+
+    #[test]
+    fn extension_matches_known_sops_suffixes() {
+        assert!(detect_by_extension(Path::new("secrets.sops.yaml")));
+        assert!(detect_by_extension(Path::new("secrets.sops.json")));
+        assert!(detect_by_extension(Path::new("config.enc.yaml")));
+        assert!(detect_by_extension(Path::new("config.enc.json")));
+        assert!(detect_by_extension(Path::new("creds.sops")));
+        assert!(!detect_by_extension(Path::new("plain.yaml")));
+    }
+}
@@ -0,0 +1,281 @@
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::fs;
+
+use crate::atomic::atomic_write;
+use crate::detect::is_sops_encrypted;
+use crate::lock::lock_path;
+use crate::runner::{encrypt_to_bytes, CommandRunner};
+
+/// Name of the directory (rooted at wherever a `Journal` is opened) that
+/// holds the journal file and crash-recovery backups. Exposed so callers
+/// that walk or watch a tree (e.g. the context-server's file watcher) can
+/// exclude it rather than re-deriving the name themselves.
+pub const JOURNAL_DIR: &str = ".sops-zed";
+const JOURNAL_FILE: &str = "journal";
+const BACKUPS_DIR: &str = "backups";
+
+/// Crash-recovery journal rooted at a given directory: before a file is
+/// decrypted in place, its original ciphertext is backed up here and an
+/// entry is appended to the journal. If the process is killed before the
+/// file is re-encrypted, `replay` restores every still-journaled file from
+/// its backup the next time the journal is opened, so plaintext is never
+/// left stranded on disk.
+pub struct Journal {
+    root: PathBuf,
+}
+
+impl Journal {
+    /// Opens the journal rooted at `root` (its state lives under
+    /// `<root>/.sops-zed`).
+    pub fn at(root: impl Into<PathBuf>) -> Self {
+        Journal { root: root.into() }
+    }
+
+    fn dir(&self) -> PathBuf {
+        self.root.join(JOURNAL_DIR)
+    }
+
+    fn path(&self) -> PathBuf {
+        self.dir().join(JOURNAL_FILE)
+    }
+
+    fn backups_dir(&self) -> PathBuf {
+        self.dir().join(BACKUPS_DIR)
+    }
+
+    /// Records that `path` is about to be decrypted in place, backing up its
+    /// original ciphertext so it can be recovered if the process crashes
+    /// before the file is re-encrypted.
+    pub fn record(&self, path: &Path, original: &[u8]) -> io::Result<()> {
+        fs::create_dir_all(self.backups_dir())?;
+        let key = path_key(path);
+        fs::write(self.backups_dir().join(&key), original)?;
+
+        let mut journal = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(self.path())?;
+        writeln!(journal, "{}\t{}", key, path.display())
+    }
+
+    /// Clears the journal entry and backup for `path` once it's safely
+    /// re-encrypted (or closed) again.
+    pub fn clear(&self, path: &Path) -> io::Result<()> {
+        let key = path_key(path);
+        fs::remove_file(self.backups_dir().join(&key)).ok();
+
+        let jpath = self.path();
+        let Ok(content) = fs::read_to_string(&jpath) else {
+            return Ok(());
+        };
+        let prefix = format!("{}\t", key);
+        let remaining: String = content
+            .lines()
+            .filter(|line| !line.starts_with(&prefix))
+            .map(|line| format!("{}\n", line))
+            .collect();
+        atomic_write(&jpath, remaining.as_bytes())
+    }
+
+    /// Replays the journal: any path still marked "decrypted" from a
+    /// previous run is reconciled back to ciphertext, and its journal entry
+    /// is cleared.
+    ///
+    /// The crash may have happened either before or after the user's editor
+    /// wrote out new plaintext, so the on-disk content at replay time might
+    /// be further along than the backed-up ciphertext. Restoring the backup
+    /// unconditionally would silently throw away that newer plaintext, so we
+    /// only fall back to it when the on-disk content isn't already
+    /// ciphertext: if it is, we assume it's already been reconciled (or was
+    /// never touched) and leave it alone; otherwise we try to re-encrypt
+    /// what's actually on disk with `sops`, and only restore the original
+    /// backup if that re-encryption fails (e.g. `sops` isn't on PATH here).
+    pub fn replay(&self, runner: &dyn CommandRunner, sops_path: &Path) {
+        let jpath = self.path();
+        let Ok(content) = fs::read_to_string(&jpath) else {
+            return;
+        };
+
+        for line in content.lines() {
+            let Some((key, path_str)) = line.split_once('\t') else {
+                continue;
+            };
+            let target = Path::new(path_str);
+            let backup_path = self.backups_dir().join(key);
+
+            let already_reconciled = fs::read_to_string(target)
+                .map(|on_disk| is_sops_encrypted(&on_disk))
+                .unwrap_or(false);
+
+            if already_reconciled {
+                eprintln!("✅ {} is already ciphertext, nothing to recover", path_str);
+            } else if let Ok(ciphertext) = encrypt_to_bytes(runner, sops_path, target) {
+                match atomic_write(target, &ciphertext) {
+                    Ok(()) => eprintln!("🔁 Re-encrypted {} left decrypted by a crash", path_str),
+                    Err(e) => eprintln!("Failed to write re-encrypted {}: {}", path_str, e),
+                }
+            } else if let Ok(original) = fs::read(&backup_path) {
+                if let Err(e) = atomic_write(target, &original) {
+                    eprintln!("Failed to restore {} from journal: {}", path_str, e);
+                } else {
+                    eprintln!(
+                        "🔁 Restored {} from crash-recovery journal (could not re-encrypt on-disk plaintext)",
+                        path_str
+                    );
+                }
+            }
+
+            fs::remove_file(&backup_path).ok();
+
+            // Any lock held on this path belonged to the process that
+            // journaled it; that process is gone (we're replaying because
+            // it never cleared this entry), so the lock is definitely
+            // orphaned rather than merely contended.
+            fs::remove_file(lock_path(target)).ok();
+        }
+
+        fs::remove_file(&jpath).ok();
+    }
+}
+
+/// Stable, filesystem-safe key derived from a path, used as its backup file name.
+fn path_key(path: &Path) -> String {
+    let mut hasher = DefaultHasher::new();
+    path.hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::runner::SopsError;
+    use std::process;
+
+    /// A runner that always fails, standing in for an environment where
+    /// `sops` isn't on PATH (re-encryption should fall back to the backup).
+    struct FailingRunner;
+
+    impl CommandRunner for FailingRunner {
+        fn run(&self, _sops_path: &Path, _args: &[&str]) -> Result<Vec<u8>, SopsError> {
+            Err(SopsError("sops not found".to_string()))
+        }
+    }
+
+    /// A runner that succeeds, returning canned ciphertext, standing in for
+    /// a real `sops -e` re-encrypting whatever plaintext is on disk.
+    struct SucceedingRunner {
+        ciphertext: &'static [u8],
+    }
+
+    impl CommandRunner for SucceedingRunner {
+        fn run(&self, _sops_path: &Path, _args: &[&str]) -> Result<Vec<u8>, SopsError> {
+            Ok(self.ciphertext.to_vec())
+        }
+    }
+
+    fn scratch_root(tag: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("sops-core-journal-{}-{}", tag, process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn record_then_clear_removes_backup_and_entry() {
+        let root = scratch_root("record-clear");
+        let secret = root.join("secret.yaml");
+        let journal = Journal::at(&root);
+
+        journal.record(&secret, b"ciphertext").unwrap();
+        assert!(journal.path().exists());
+
+        journal.clear(&secret).unwrap();
+        let content = fs::read_to_string(journal.path()).unwrap_or_default();
+        assert!(!content.contains(&secret.display().to_string()));
+
+        fs::remove_dir_all(&root).ok();
+    }
+
+    #[test]
+    fn replay_restores_ciphertext_when_reencryption_is_unavailable() {
+        let root = scratch_root("replay");
+        let secret = root.join("secret.yaml");
+        fs::write(&secret, b"plaintext-left-on-disk").unwrap();
+
+        let journal = Journal::at(&root);
+        journal.record(&secret, b"original-ciphertext").unwrap();
+
+        // Simulate a crash: nothing calls `clear`, so the entry is replayed
+        // by a fresh `Journal` the next time the process starts. `sops`
+        // isn't reachable, so replay must fall back to the backup.
+        Journal::at(&root).replay(&FailingRunner, Path::new("sops"));
+
+        assert_eq!(fs::read(&secret).unwrap(), b"original-ciphertext");
+        assert!(!journal.path().exists());
+
+        fs::remove_dir_all(&root).ok();
+    }
+
+    #[test]
+    fn replay_reencrypts_on_disk_plaintext_instead_of_restoring_the_backup() {
+        let root = scratch_root("replay-reencrypt");
+        let secret = root.join("secret.yaml");
+        fs::write(&secret, b"new-plaintext-saved-before-the-crash").unwrap();
+
+        let journal = Journal::at(&root);
+        journal.record(&secret, b"stale-original-ciphertext").unwrap();
+
+        // `sops` is available this time and re-encrypts whatever is
+        // currently on disk, which must win over the older backup.
+        let runner = SucceedingRunner { ciphertext: b"sops:\nkey: ENC[fresh]" };
+        Journal::at(&root).replay(&runner, Path::new("sops"));
+
+        assert_eq!(fs::read(&secret).unwrap(), b"sops:\nkey: ENC[fresh]");
+        assert!(!journal.path().exists());
+
+        fs::remove_dir_all(&root).ok();
+    }
+
+    #[test]
+    fn replay_leaves_already_reconciled_ciphertext_untouched() {
+        let root = scratch_root("replay-already-done");
+        let secret = root.join("secret.yaml");
+        let already_encrypted = b"sops:\nkey: ENC[already-done]".to_vec();
+        fs::write(&secret, &already_encrypted).unwrap();
+
+        let journal = Journal::at(&root);
+        journal.record(&secret, b"even-older-ciphertext").unwrap();
+
+        // The file was already re-encrypted before the crash (only `clear`
+        // never ran); replay must not overwrite it with the stale backup.
+        Journal::at(&root).replay(&FailingRunner, Path::new("sops"));
+
+        assert_eq!(fs::read(&secret).unwrap(), already_encrypted);
+        assert!(!journal.path().exists());
+
+        fs::remove_dir_all(&root).ok();
+    }
+
+    #[test]
+    fn replay_removes_the_orphaned_lock_for_a_journaled_path() {
+        let root = scratch_root("replay-lock-cleanup");
+        let secret = root.join("secret.yaml");
+        fs::write(&secret, b"plaintext-left-on-disk").unwrap();
+
+        let journal = Journal::at(&root);
+        journal.record(&secret, b"original-ciphertext").unwrap();
+
+        // The process that journaled this path also held its advisory lock;
+        // simulate it crashing without ever releasing the lock.
+        let lock = lock_path(&secret);
+        fs::write(&lock, process::id().to_string()).unwrap();
+
+        Journal::at(&root).replay(&FailingRunner, Path::new("sops"));
+
+        assert!(!lock.exists(), "replay should have removed the orphaned lock");
+
+        fs::remove_dir_all(&root).ok();
+    }
+}
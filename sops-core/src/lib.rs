@@ -0,0 +1,15 @@
+// sops-core: the canonical decrypt/encrypt/detection logic shared by the
+// extension cdylib (`sops-zed`) and the `sops_context_server` binary. Both
+// previously carried their own copies of this logic and had drifted apart;
+// this crate is the single implementation both depend on.
+mod atomic;
+mod detect;
+mod journal;
+mod lock;
+mod runner;
+
+pub use atomic::atomic_write;
+pub use detect::{detect_by_extension, is_sops_encrypted};
+pub use journal::{Journal, JOURNAL_DIR};
+pub use lock::FileLock;
+pub use runner::{decrypt_to_bytes, encrypt_to_bytes, CommandRunner, SopsError, SystemCommandRunner};
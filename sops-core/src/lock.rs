@@ -0,0 +1,147 @@
+use std::io::{self, Write};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// A lock older than this with no parseable (or no longer running) owner
+/// pid is treated as abandoned, e.g. left behind by a process killed
+/// before it could remove its own lock file.
+const STALE_AFTER: Duration = Duration::from_secs(5 * 60);
+
+/// Path of the advisory lock file for `target`.
+pub fn lock_path(target: &Path) -> PathBuf {
+    let mut lock_name = target.as_os_str().to_os_string();
+    lock_name.push(".sops-lock");
+    PathBuf::from(lock_name)
+}
+
+/// An advisory lock file (`<path>.sops-lock`) that serializes decrypt/encrypt
+/// of a secret file across both the extension cdylib and the context-server
+/// binary. Acquisition is non-blocking: if the lock file already exists and
+/// its owner is still alive, `try_acquire` returns `None` rather than
+/// waiting, so callers skip the operation instead of risking a
+/// double-encrypt race. A lock whose owning process has died (or, failing
+/// that, one old enough to be considered abandoned) is treated as stale and
+/// broken automatically, so a crash doesn't permanently wedge a file out of
+/// future decrypt/encrypt attempts. The lock file records the owning pid and
+/// is removed when the `FileLock` is dropped.
+pub struct FileLock {
+    lock_path: PathBuf,
+}
+
+impl FileLock {
+    pub fn try_acquire(target: &Path) -> Option<FileLock> {
+        let lock_path = lock_path(target);
+
+        if create_lock_file(&lock_path).is_ok() {
+            return Some(FileLock { lock_path });
+        }
+
+        if is_stale(&lock_path) {
+            fs::remove_file(&lock_path).ok();
+            if create_lock_file(&lock_path).is_ok() {
+                return Some(FileLock { lock_path });
+            }
+        }
+
+        None
+    }
+}
+
+impl Drop for FileLock {
+    fn drop(&mut self) {
+        fs::remove_file(&self.lock_path).ok();
+    }
+}
+
+/// Creates `lock_path`, failing if it already exists, recording our own pid
+/// as its contents so a later `is_stale` check can tell whether the owner
+/// is still running.
+fn create_lock_file(lock_path: &Path) -> io::Result<()> {
+    let mut file = fs::OpenOptions::new().write(true).create_new(true).open(lock_path)?;
+    write!(file, "{}", std::process::id())
+}
+
+/// A lock is stale if its recorded owner pid is no longer running, or (when
+/// the pid can't be read or checked) if the lock file is old enough that its
+/// owner almost certainly crashed rather than just being slow.
+fn is_stale(lock_path: &Path) -> bool {
+    let Ok(content) = fs::read_to_string(lock_path) else {
+        return false;
+    };
+
+    if let Ok(pid) = content.trim().parse::<u32>() {
+        if !process_alive(pid) {
+            return true;
+        }
+    }
+
+    fs::metadata(lock_path)
+        .and_then(|meta| meta.modified())
+        .and_then(|modified| modified.elapsed().map_err(io::Error::other))
+        .map(|age| age > STALE_AFTER)
+        .unwrap_or(false)
+}
+
+/// Returns `true` if a process with `pid` appears to still be running.
+#[cfg(unix)]
+fn process_alive(pid: u32) -> bool {
+    // Signal 0 sends nothing; it only checks that the pid exists and that
+    // we're allowed to signal it, which is exactly what we need here.
+    let result = unsafe { libc::kill(pid as libc::pid_t, 0) };
+    result == 0 || io::Error::last_os_error().kind() == io::ErrorKind::PermissionDenied
+}
+
+#[cfg(not(unix))]
+fn process_alive(_pid: u32) -> bool {
+    // No portable liveness check; fall back to the mtime-based staleness
+    // check in `is_stale`.
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    #[test]
+    fn second_acquire_fails_while_first_is_held() {
+        let target = std::env::temp_dir().join(format!("sops-core-lock-test-{}", process::id()));
+
+        let first = FileLock::try_acquire(&target);
+        assert!(first.is_some());
+        assert!(FileLock::try_acquire(&target).is_none());
+
+        drop(first);
+        assert!(FileLock::try_acquire(&target).is_some());
+
+        fs::remove_file(lock_path(&target)).ok();
+    }
+
+    #[test]
+    fn acquire_breaks_a_lock_left_by_a_dead_process() {
+        let target = std::env::temp_dir().join(format!("sops-core-lock-stale-{}", process::id()));
+        let path = lock_path(&target);
+
+        // A pid that's vanishingly unlikely to be running, simulating a
+        // lock left behind by a process that crashed before cleaning up.
+        fs::write(&path, "999999999").unwrap();
+
+        assert!(FileLock::try_acquire(&target).is_some());
+
+        fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn acquire_respects_a_lock_held_by_a_live_process() {
+        let target = std::env::temp_dir().join(format!("sops-core-lock-live-{}", process::id()));
+        let path = lock_path(&target);
+
+        // Our own pid is definitely alive, so this lock must be respected.
+        fs::write(&path, process::id().to_string()).unwrap();
+
+        assert!(FileLock::try_acquire(&target).is_none());
+
+        fs::remove_file(&path).ok();
+    }
+}
@@ -0,0 +1,132 @@
+use std::fmt;
+use std::path::Path;
+use std::process;
+
+/// An error from running `sops` or from the plumbing around it. Carries a
+/// human-readable message only, matching how both call sites (a `zed::Result<T>`
+/// string error and an `anyhow::Result<T>`) want to report failures.
+#[derive(Debug)]
+pub struct SopsError(pub String);
+
+impl fmt::Display for SopsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for SopsError {}
+
+impl From<SopsError> for String {
+    fn from(err: SopsError) -> Self {
+        err.0
+    }
+}
+
+/// Runs the `sops` binary. Abstracted behind a trait so the decrypt/encrypt
+/// logic can be unit-tested without a real `sops` binary on PATH.
+pub trait CommandRunner {
+    fn run(&self, sops_path: &Path, args: &[&str]) -> Result<Vec<u8>, SopsError>;
+}
+
+/// The real `CommandRunner`, which shells out to `sops`.
+pub struct SystemCommandRunner;
+
+impl CommandRunner for SystemCommandRunner {
+    fn run(&self, sops_path: &Path, args: &[&str]) -> Result<Vec<u8>, SopsError> {
+        let output = process::Command::new(sops_path)
+            .args(args)
+            .output()
+            .map_err(|e| SopsError(format!("failed to execute {}: {}", sops_path.display(), e)))?;
+
+        if !output.status.success() {
+            return Err(SopsError(format!(
+                "{} {} failed: {}",
+                sops_path.display(),
+                args.join(" "),
+                String::from_utf8_lossy(&output.stderr)
+            )));
+        }
+
+        Ok(output.stdout)
+    }
+}
+
+/// Decrypts `target` with `sops -d`, returning the plaintext. The caller is
+/// responsible for journaling the original ciphertext and writing the
+/// plaintext back to disk (typically via `atomic_write`).
+pub fn decrypt_to_bytes(
+    runner: &dyn CommandRunner,
+    sops_path: &Path,
+    target: &Path,
+) -> Result<Vec<u8>, SopsError> {
+    runner.run(sops_path, &["-d", &target.to_string_lossy()])
+}
+
+/// Encrypts `target` with `sops -e`, returning the ciphertext. `target` must
+/// already hold the plaintext to encrypt; the caller is responsible for
+/// writing the returned ciphertext back to disk.
+pub fn encrypt_to_bytes(
+    runner: &dyn CommandRunner,
+    sops_path: &Path,
+    target: &Path,
+) -> Result<Vec<u8>, SopsError> {
+    runner.run(sops_path, &["-e", &target.to_string_lossy()])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    /// Records the args it was called with and returns a canned result,
+    /// so decrypt/encrypt can be tested without a real `sops` binary.
+    struct MockRunner {
+        response: Result<Vec<u8>, String>,
+        seen_args: RefCell<Vec<String>>,
+    }
+
+    impl CommandRunner for MockRunner {
+        fn run(&self, _sops_path: &Path, args: &[&str]) -> Result<Vec<u8>, SopsError> {
+            *self.seen_args.borrow_mut() = args.iter().map(|s| s.to_string()).collect();
+            self.response.clone().map_err(SopsError)
+        }
+    }
+
+    #[test]
+    fn decrypt_passes_dash_d_and_the_target_path() {
+        let runner = MockRunner {
+            response: Ok(b"plaintext".to_vec()),
+            seen_args: RefCell::new(Vec::new()),
+        };
+
+        let result = decrypt_to_bytes(&runner, Path::new("sops"), Path::new("secrets.yaml")).unwrap();
+
+        assert_eq!(result, b"plaintext");
+        assert_eq!(*runner.seen_args.borrow(), vec!["-d", "secrets.yaml"]);
+    }
+
+    #[test]
+    fn encrypt_passes_dash_e_and_the_target_path() {
+        let runner = MockRunner {
+            response: Ok(b"ciphertext".to_vec()),
+            seen_args: RefCell::new(Vec::new()),
+        };
+
+        let result = encrypt_to_bytes(&runner, Path::new("sops"), Path::new("secrets.yaml")).unwrap();
+
+        assert_eq!(result, b"ciphertext");
+        assert_eq!(*runner.seen_args.borrow(), vec!["-e", "secrets.yaml"]);
+    }
+
+    #[test]
+    fn propagates_runner_failure() {
+        let runner = MockRunner {
+            response: Err("sops: no key found".to_string()),
+            seen_args: RefCell::new(Vec::new()),
+        };
+
+        let err = decrypt_to_bytes(&runner, Path::new("sops"), Path::new("secrets.yaml")).unwrap_err();
+
+        assert_eq!(err.0, "sops: no key found");
+    }
+}
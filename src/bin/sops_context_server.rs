@@ -1,18 +1,174 @@
 // src/bin/sops_context_server.rs
 use anyhow::{bail, Context, Result};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
 use notify::{RecommendedWatcher, RecursiveMode, Watcher, Event, EventKind};
 use notify::event::AccessKind;
+use regex::Regex;
+use once_cell::sync::Lazy;
+use sops_core::{
+    atomic_write, decrypt_to_bytes, detect_by_extension, encrypt_to_bytes, is_sops_encrypted,
+    FileLock, Journal, SystemCommandRunner, JOURNAL_DIR,
+};
 use std::{
+    collections::HashMap,
     fs,
     path::{Path, PathBuf},
-    process::Command,
     sync::mpsc::channel,
+    sync::Mutex,
 };
 
+// Advisory per-file locks held for as long as a file stays decrypted on
+// disk by this server, keyed by path — acquired in `decrypt` and released
+// only once the matching `encrypt` actually completes, mirroring
+// `HELD_LOCKS` in the extension cdylib (`src/sops.rs`) so the lock covers
+// the whole decrypted-on-disk window rather than just the instant of the
+// `sops` invocation.
+static HELD_LOCKS: Lazy<Mutex<HashMap<String, FileLock>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+// Directories that are never SOPS-managed and are expensive to walk/read on
+// large trees. These are excluded even when the repo has no `.gitignore`.
+// `JOURNAL_DIR` in particular must never be walked/watched/sniffed: it
+// holds crash-recovery backups that are byte-for-byte ciphertext copies, so
+// treating them as regular SOPS files would let the watcher "decrypt" and
+// overwrite the very backups the journal exists to protect.
+const DEFAULT_EXCLUDE_GLOBS: &[&str] = &["target", ".git", "node_modules", JOURNAL_DIR];
+
+/// Scopes file watching to what `.sops.yaml` actually encrypts: a compiled
+/// set of `creation_rules[].path_regex` patterns, plus a `.gitignore` (and
+/// default exclude globs) matcher so build artifacts and VCS metadata are
+/// never walked or read.
+struct SopsConfig {
+    path_regexes: Vec<Regex>,
+    ignore: Option<Gitignore>,
+}
+
+impl SopsConfig {
+    fn load(root: &Path) -> Self {
+        SopsConfig {
+            path_regexes: load_creation_rules(root),
+            ignore: load_ignore_set(root),
+        }
+    }
+
+    fn is_ignored(&self, path: &Path) -> bool {
+        match &self.ignore {
+            Some(ignore) => ignore.matched(path, path.is_dir()).is_ignore(),
+            None => false,
+        }
+    }
+
+    fn matches_creation_rule(&self, path: &Path) -> bool {
+        let path_str = path.to_string_lossy();
+        self.path_regexes.iter().any(|re| re.is_match(&path_str))
+    }
+}
+
+/// Parses `<root>/.sops.yaml` for `creation_rules[].path_regex` entries.
+/// Returns an empty list (never an error) if the file is missing or has no
+/// rules, since `.sops.yaml` is optional and `is_sops_file` falls back to
+/// extension/content sniffing in that case.
+fn load_creation_rules(root: &Path) -> Vec<Regex> {
+    let Ok(content) = fs::read_to_string(root.join(".sops.yaml")) else {
+        return Vec::new();
+    };
+
+    let mut regexes = Vec::new();
+    let mut in_creation_rules = false;
+    for line in content.lines() {
+        let trimmed = line.trim();
+        if trimmed == "creation_rules:" {
+            in_creation_rules = true;
+            continue;
+        }
+        if !in_creation_rules {
+            continue;
+        }
+        // A new top-level key ends the `creation_rules` block.
+        if !line.starts_with(' ') && !line.starts_with('-') && !trimmed.is_empty() {
+            break;
+        }
+        let Some(value) = trimmed
+            .strip_prefix("- path_regex:")
+            .or_else(|| trimmed.strip_prefix("path_regex:"))
+        else {
+            continue;
+        };
+        let pattern = value.trim().trim_matches(|c| c == '\'' || c == '"');
+        match Regex::new(pattern) {
+            Ok(re) => regexes.push(re),
+            Err(e) => eprintln!("⚠️ Invalid path_regex {:?} in .sops.yaml: {}", pattern, e),
+        }
+    }
+
+    regexes
+}
+
+/// Builds a `.gitignore`-aware matcher rooted at `root`, seeded with
+/// `DEFAULT_EXCLUDE_GLOBS` so common build/VCS directories are always
+/// excluded even without a `.gitignore` present.
+fn load_ignore_set(root: &Path) -> Option<Gitignore> {
+    let mut builder = GitignoreBuilder::new(root);
+
+    let gitignore_path = root.join(".gitignore");
+    if gitignore_path.is_file() {
+        if let Some(e) = builder.add(&gitignore_path) {
+            eprintln!("⚠️ Failed to parse .gitignore: {}", e);
+        }
+    }
+
+    for pattern in DEFAULT_EXCLUDE_GLOBS {
+        if let Err(e) = builder.add_line(None, pattern) {
+            eprintln!("⚠️ Invalid exclude glob {:?}: {}", pattern, e);
+        }
+    }
+
+    match builder.build() {
+        Ok(ignore) => Some(ignore),
+        Err(e) => {
+            eprintln!("⚠️ Failed to build ignore set: {}", e);
+            None
+        }
+    }
+}
+
+fn journal() -> Journal {
+    Journal::at(".")
+}
+
+/// Registers a non-recursive watch on `dir` and every subdirectory under it
+/// that `config` doesn't exclude, so ignored directories (`target/`,
+/// `.git/`, `.gitignore`'d paths, ...) are never walked into or watched —
+/// `notify`'s own `RecursiveMode::Recursive` has no way to prune a subtree,
+/// so pruning has to happen here, one directory at a time, instead.
+fn watch_tree(watcher: &mut RecommendedWatcher, config: &SopsConfig, dir: &Path) {
+    if config.is_ignored(dir) {
+        return;
+    }
+
+    if let Err(e) = watcher.watch(dir, RecursiveMode::NonRecursive) {
+        eprintln!("⚠️ Failed to watch {}: {}", dir.display(), e);
+        return;
+    }
+
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            watch_tree(watcher, config, &path);
+        }
+    }
+}
+
 fn main() -> Result<()> {
     // Log startup for debugging
     eprintln!("🔒 sops-context-server starting...");
 
+    // Scope watching to what .sops.yaml actually encrypts, and skip
+    // .gitignore'd / build-artifact directories entirely.
+    let config = SopsConfig::load(Path::new("."));
+
     // Locate `sops` in PATH
     let sops_path = match which::which("sops") {
         Ok(path) => {
@@ -25,14 +181,16 @@ fn main() -> Result<()> {
         }
     };
 
-    // Watch entire workspace
+    // Reconcile any files left decrypted on disk by a previous crash, now
+    // that we can re-encrypt them with the located `sops` binary.
+    journal().replay(&SystemCommandRunner, &sops_path);
+
     let (tx, rx) = channel();
     let mut watcher: RecommendedWatcher = RecommendedWatcher::new(tx, notify::Config::default())
         .context("failed to initialize file watcher")?;
 
-    eprintln!("🔍 Watching current directory for changes");
-    watcher.watch(Path::new("."), RecursiveMode::Recursive)
-        .context("failed to watch workspace")?;
+    eprintln!("🔍 Watching directories not excluded by .gitignore / default excludes");
+    watch_tree(&mut watcher, &config, Path::new("."));
 
     eprintln!("🔒 sops-context-server running... watching for encrypted files");
 
@@ -48,10 +206,10 @@ fn main() -> Result<()> {
                             AccessKind::Open(_) => {
                                 // File was opened
                                 for path in paths {
-                                    if is_sops_file(&path) {
+                                    if is_sops_file(&config, &path) {
                                         eprintln!("📂 File opened: {}", path.display());
                                         if let Err(e) = decrypt(&sops_path, &path) {
-                                            eprintln!("❌ Decrypt error on open: {}", e);
+                                            eprintln!("❌ Decrypt error on open: {:?}", e);
                                         }
                                     }
                                 }
@@ -59,10 +217,10 @@ fn main() -> Result<()> {
                             AccessKind::Close(_) => {
                                 // File was closed
                                 for path in paths {
-                                    if is_sops_file(&path) {
+                                    if is_sops_file(&config, &path) {
                                         eprintln!("🚪 File closed: {}", path.display());
                                         if let Err(e) = encrypt(&sops_path, &path) {
-                                            eprintln!("❌ Encrypt error on close: {}", e);
+                                            eprintln!("❌ Encrypt error on close: {:?}", e);
                                         }
                                     }
                                 }
@@ -73,23 +231,30 @@ fn main() -> Result<()> {
                     EventKind::Modify(_) => {
                         // File was modified/saved
                         for path in paths {
-                            if is_sops_file(&path) {
+                            if is_sops_file(&config, &path) {
                                 eprintln!("💾 File modified: {}", path.display());
                                 // When a file is modified and it's a SOPS file,
                                 // we should first ensure it's decrypted for editing
                                 if let Err(e) = ensure_decrypted(&sops_path, &path) {
-                                    eprintln!("❌ Ensure decrypted error: {}", e);
+                                    eprintln!("❌ Ensure decrypted error: {:?}", e);
                                 }
                             }
                         }
                     },
                     EventKind::Create(_) => {
-                        // New file created
+                        // New file or directory created
                         for path in paths {
-                            if is_sops_file(&path) {
+                            if path.is_dir() {
+                                // Extend watching to the new subtree (pruning
+                                // anything it contains that's ignored), since
+                                // our watches aren't recursive.
+                                watch_tree(&mut watcher, &config, &path);
+                                continue;
+                            }
+                            if is_sops_file(&config, &path) {
                                 eprintln!("🆕 New SOPS file created: {}", path.display());
                                 if let Err(e) = check_and_process_file(&sops_path, &path) {
-                                    eprintln!("❌ Processing error on create: {}", e);
+                                    eprintln!("❌ Processing error on create: {:?}", e);
                                 }
                             }
                         }
@@ -107,7 +272,7 @@ fn main() -> Result<()> {
 fn check_and_process_file(sops: &Path, path: &PathBuf) -> Result<()> {
     // Check if the file appears to be encrypted
     let content = fs::read_to_string(path)?;
-    if content.contains("ENC[") || content.contains("sops:") {
+    if is_sops_encrypted(&content) {
         eprintln!("🔍 File appears to be encrypted, decrypting: {}", path.display());
         decrypt(sops, path)?;
     } else {
@@ -119,7 +284,7 @@ fn check_and_process_file(sops: &Path, path: &PathBuf) -> Result<()> {
 fn ensure_decrypted(sops: &Path, path: &PathBuf) -> Result<()> {
     // Read the content to check if it's already decrypted
     let content = fs::read_to_string(path)?;
-    if content.contains("ENC[") {
+    if is_sops_encrypted(&content) {
         eprintln!("🔓 File needs decryption: {}", path.display());
         decrypt(sops, path)?;
     } else {
@@ -128,32 +293,33 @@ fn ensure_decrypted(sops: &Path, path: &PathBuf) -> Result<()> {
     Ok(())
 }
 
-fn is_sops_file(path: &PathBuf) -> bool {
+fn is_sops_file(config: &SopsConfig, path: &PathBuf) -> bool {
     // Check if the file exists and is a file
     if !path.is_file() {
         return false;
     }
 
-    // Get the file name as a string
-    let file_name = match path.file_name().and_then(|n| n.to_str()) {
-        Some(name) => name,
-        None => return false,
-    };
+    // Never walk/read into .gitignore'd or default-excluded directories.
+    if config.is_ignored(path) {
+        return false;
+    }
 
-    // Check for common SOPS filename patterns
-    if file_name.ends_with(".sops.yaml") ||
-       file_name.ends_with(".sops.json") ||
-       file_name.ends_with(".enc.yaml") ||
-       file_name.ends_with(".enc.json") ||
-       file_name.ends_with(".sops") {
+    // .sops.yaml creation_rules are authoritative when present.
+    if config.matches_creation_rule(path) {
+        eprintln!("📄 Matched .sops.yaml creation_rule: {}", path.display());
+        return true;
+    }
+
+    // Fall back to extension/content sniffing for files not covered by an
+    // explicit creation_rule (or when there's no .sops.yaml at all).
+    if detect_by_extension(path) {
         eprintln!("📄 Found SOPS file by extension: {}", path.display());
         return true;
     }
 
     // Check file content (as a fallback)
     if let Ok(content) = fs::read_to_string(path) {
-        if (content.contains("sops:") && content.contains("ENC[")) ||
-           content.contains("encrypted_suffix") {
+        if is_sops_encrypted(&content) {
             eprintln!("📄 Found SOPS file by content: {}", path.display());
             return true;
         }
@@ -163,21 +329,52 @@ fn is_sops_file(path: &PathBuf) -> bool {
 }
 
 fn decrypt(sops: &Path, path: &PathBuf) -> Result<()> {
+    let path_str = path.to_string_lossy().to_string();
+
+    if !HELD_LOCKS.lock().unwrap().contains_key(&path_str) {
+        let Some(lock) = FileLock::try_acquire(path) else {
+            eprintln!("⏭️ {} is locked by another worker, skipping decrypt", path.display());
+            return Ok(());
+        };
+        HELD_LOCKS.lock().unwrap().insert(path_str.clone(), lock);
+    }
+
+    // A transient failure partway through must not wedge this path's lock
+    // (and its `<path>.sops-lock` file) for the rest of the process's
+    // life, so any error releases it here; only a clean decrypt leaves it
+    // held for the now-active decrypted-on-disk window.
+    let result = decrypt_locked(sops, path);
+    if result.is_err() {
+        HELD_LOCKS.lock().unwrap().remove(&path_str);
+    }
+    result
+}
+
+fn decrypt_locked(sops: &Path, path: &PathBuf) -> Result<()> {
     eprintln!("🔑 Running: {} -d {}", sops.display(), path.display());
 
-    let output = Command::new(sops)
-        .arg("-d")
-        .arg(path)
-        .output()
-        .context(format!("running `{} -d {}` failed", sops.display(), path.display()))?;
+    // Back up the ciphertext to the crash-recovery journal before
+    // overwriting `path` with plaintext.
+    let ciphertext = fs::read(path).context("reading ciphertext for journal backup failed")?;
 
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        eprintln!("❌ Decrypt error: {}", stderr);
-        bail!("sops decrypt error: {}", stderr);
+    // Only decrypt if the file is actually still ciphertext: a second Open
+    // event on a file we already decrypted (editors/LSPs routinely re-open
+    // a file; `HELD_LOCKS` keeps us from re-acquiring the lock but doesn't
+    // otherwise short-circuit) would otherwise journal the current
+    // plaintext as if it were the ciphertext backup, corrupting the real
+    // crash-recovery copy under `.sops-zed/backups/`.
+    if !is_sops_encrypted(&String::from_utf8_lossy(&ciphertext)) {
+        eprintln!("⏭️ File already decrypted, skipping: {}", path.display());
+        return Ok(());
     }
 
-    fs::write(path, &output.stdout)
+    journal().record(path, &ciphertext).context("recording journal entry failed")?;
+
+    let plaintext = decrypt_to_bytes(&SystemCommandRunner, sops, path)
+        .map_err(|e| anyhow::anyhow!(e))
+        .context(format!("running `{} -d {}` failed", sops.display(), path.display()))?;
+
+    atomic_write(path, &plaintext)
         .context("writing decrypted content failed")?;
 
     eprintln!("✅ Decrypted {}", path.display());
@@ -185,30 +382,57 @@ fn decrypt(sops: &Path, path: &PathBuf) -> Result<()> {
 }
 
 fn encrypt(sops: &Path, path: &PathBuf) -> Result<()> {
-    eprintln!("🔒 Running: {} -e -i {}", sops.display(), path.display());
+    let path_str = path.to_string_lossy().to_string();
+
+    if !HELD_LOCKS.lock().unwrap().contains_key(&path_str) {
+        let Some(lock) = FileLock::try_acquire(path) else {
+            eprintln!("⏭️ {} is locked by another worker, skipping encrypt", path.display());
+            return Ok(());
+        };
+        HELD_LOCKS.lock().unwrap().insert(path_str.clone(), lock);
+    }
+
+    // Same reasoning as `decrypt`: release the lock on any error so a
+    // transient `sops` failure doesn't wedge this path forever.
+    let result = encrypt_locked(sops, path);
+    if result.is_err() {
+        HELD_LOCKS.lock().unwrap().remove(&path_str);
+    }
+    result
+}
+
+fn encrypt_locked(sops: &Path, path: &PathBuf) -> Result<()> {
+    let path_str = path.to_string_lossy().to_string();
+
+    eprintln!("🔒 Running: {} -e {}", sops.display(), path.display());
 
     // First read the current content
     let content = fs::read_to_string(path)?;
 
     // Only encrypt if it's not already encrypted
-    if content.contains("ENC[") {
+    if is_sops_encrypted(&content) {
         eprintln!("⏭️ File already encrypted, skipping: {}", path.display());
+        HELD_LOCKS.lock().unwrap().remove(&path_str);
         return Ok(());
     }
 
-    let output = Command::new(sops)
-        .arg("-e")
-        .arg("-i")  // Use in-place editing
-        .arg(path)
-        .output()
-        .context(format!("running `{} -e -i {}` failed", sops.display(), path.display()))?;
+    // Encrypt to stdout rather than `-i` so we control the write to `path`
+    // ourselves and can make it atomic.
+    let ciphertext = encrypt_to_bytes(&SystemCommandRunner, sops, path)
+        .map_err(|e| anyhow::anyhow!(e))
+        .context(format!("running `{} -e {}` failed", sops.display(), path.display()))?;
 
-    if !output.status.success() {
-        let stderr = String::from_utf8_lossy(&output.stderr);
-        eprintln!("❌ Encrypt error: {}", stderr);
-        bail!("sops encrypt error: {}", stderr);
+    atomic_write(path, &ciphertext)
+        .context("writing encrypted content failed")?;
+
+    if let Err(e) = journal().clear(path) {
+        eprintln!("⚠️ Failed to clear journal entry for {}: {}", path.display(), e);
     }
 
+    // The file is ciphertext on disk again; release the lock held for the
+    // decrypted-on-disk window.
+    HELD_LOCKS.lock().unwrap().remove(&path_str);
+
     eprintln!("✅ Re-encrypted {}", path.display());
     Ok(())
 }
@@ -1,4 +1,6 @@
 // src/lib.rs
+mod sops;
+
 use zed_extension_api as zed;
 use zed::{ContextServerId, Project, Command, Result};
 
@@ -6,6 +8,9 @@ struct SopsExtension;
 
 impl zed::Extension for SopsExtension {
     fn new() -> Self {
+        // Reconcile any files left decrypted on disk by a previous crash
+        // before the extension starts handling document callbacks.
+        sops::replay_journal();
         SopsExtension
     }
 
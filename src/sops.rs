@@ -1,74 +1,46 @@
-use std::{fs, process};
+use std::fs;
 use std::collections::HashMap;
+use std::path::Path;
 use std::sync::Mutex;
-use zed_extension_api::{self as zed, Result};
+use zed_extension_api::Result;
 use once_cell::sync::Lazy;
+use sops_core::{atomic_write, is_sops_encrypted, decrypt_to_bytes, encrypt_to_bytes, FileLock, Journal, SystemCommandRunner};
 use std::ffi::CStr;
 use std::ffi::c_char;
 
 // Use a global static to track state between hook callbacks
 static DECRYPTED_FILES: Lazy<Mutex<HashMap<String, String>>> = Lazy::new(|| Mutex::new(HashMap::new()));
 
-struct SopsExtension;
+// Advisory per-file locks held for the duration a file stays decrypted on
+// disk, keyed the same way as `DECRYPTED_FILES` so both maps stay in sync.
+static HELD_LOCKS: Lazy<Mutex<HashMap<String, FileLock>>> = Lazy::new(|| Mutex::new(HashMap::new()));
 
-impl SopsExtension {
-    fn is_sops_encrypted(content: &str) -> bool {
-        // Check for SOPS header markers
-        content.contains("sops:") &&
-        (content.contains("encrypted_") || content.contains("ENC["))
-    }
-
-    fn decrypt_file(path: &str) -> Result<String> {
-        let output = process::Command::new("sops")
-            .arg("-d")
-            .arg(path)
-            .output()
-            .map_err(|e| format!("Failed to execute sops: {}", e))?;
-
-        if !output.status.success() {
-            return Err(format!(
-                "sops decryption failed: {}",
-                String::from_utf8_lossy(&output.stderr)
-            ));
-        }
-
-        Ok(String::from_utf8_lossy(&output.stdout).to_string())
-    }
-
-    fn encrypt_file(path: &str, content: &str) -> Result<()> {
-        // Write decrypted content to a temporary file
-        let temp_file = format!("{}.tmp", path);
-        fs::write(&temp_file, content)
-            .map_err(|e| format!("Failed to write temp file: {}", e))?;
-
-        // Encrypt the temp file and redirect output to the original file
-        let output = process::Command::new("sops")
-            .arg("-e")
-            .arg("--in-place")
-            .arg(&temp_file)
-            .output()
-            .map_err(|e| format!("Failed to execute sops: {}", e))?;
-
-        if !output.status.success() {
-            fs::remove_file(&temp_file).ok();
-            return Err(format!(
-                "sops encryption failed: {}",
-                String::from_utf8_lossy(&output.stderr)
-            ));
-        }
+fn journal() -> Journal {
+    Journal::at(".")
+}
 
-        // Move the encrypted temp file back to the original path
-        fs::rename(&temp_file, path)
-            .map_err(|e| format!("Failed to move encrypted file: {}", e))?;
+/// Reconciles any files left decrypted on disk by a previous crash. Called
+/// once from `SopsExtension::new` in `lib.rs` before the extension starts
+/// handling document callbacks.
+pub(crate) fn replay_journal() {
+    journal().replay(&SystemCommandRunner, Path::new("sops"));
+}
 
-        Ok(())
-    }
+fn decrypt_file(path: &str) -> Result<String> {
+    let bytes = decrypt_to_bytes(&SystemCommandRunner, Path::new("sops"), Path::new(path))?;
+    Ok(String::from_utf8_lossy(&bytes).to_string())
 }
 
-impl zed::Extension for SopsExtension {
-    fn new() -> Self {
-        Self
-    }
+fn encrypt_file(path: &str) -> Result<()> {
+    // The plaintext to encrypt is already on disk at `path` (callers
+    // read it from there before calling this), so `sops -e` can read it
+    // directly; only the ciphertext write-back needs to go through
+    // `atomic_write`.
+    let ciphertext = encrypt_to_bytes(&SystemCommandRunner, Path::new("sops"), Path::new(path))?;
+    atomic_write(Path::new(path), &ciphertext)
+        .map_err(|e| format!("Failed to write encrypted content: {}", e))?;
+
+    Ok(())
 }
 
 // Implement document callbacks
@@ -85,18 +57,35 @@ pub extern "C" fn open_document(_buffer_ptr: *mut u8, path: *const c_char) -> bo
 
     // Read the file content
     if let Ok(content) = fs::read_to_string(&path_str) {
-        if SopsExtension::is_sops_encrypted(&content) {
-            match SopsExtension::decrypt_file(&path_str) {
+        if is_sops_encrypted(&content) {
+            let Some(lock) = FileLock::try_acquire(Path::new(&path_str)) else {
+                eprintln!("⏭️ {} is locked by another worker, skipping decrypt on open", path_str);
+                return true;
+            };
+
+            match decrypt_file(&path_str) {
                 Ok(decrypted) => {
+                    // Back up the ciphertext to the crash-recovery journal
+                    // before writing plaintext over it.
+                    if let Err(e) = journal().record(Path::new(&path_str), content.as_bytes()) {
+                        eprintln!("Failed to journal {}: {}", path_str, e);
+                        return false;
+                    }
+
                     // Store original content for later encryption
                     let mut files = DECRYPTED_FILES.lock().unwrap();
                     files.insert(path_str.clone(), content);
+                    drop(files);
 
                     // Write decrypted content to file
-                    if let Err(e) = fs::write(&path_str, decrypted) {
+                    if let Err(e) = atomic_write(Path::new(&path_str), decrypted.as_bytes()) {
                         eprintln!("Failed to write decrypted content: {}", e);
                         return false;
                     }
+
+                    // Hold the lock for as long as the file stays decrypted
+                    // on disk; it's released when `close_document` runs.
+                    HELD_LOCKS.lock().unwrap().insert(path_str.clone(), lock);
                 },
                 Err(e) => {
                     eprintln!("Failed to decrypt SOPS file: {}", e);
@@ -124,19 +113,26 @@ pub extern "C" fn save_document(_buffer_ptr: *mut u8, path: *const c_char) -> bo
 
     let files = DECRYPTED_FILES.lock().unwrap();
     if files.contains_key(&path_str) {
-        // The file content will be read from disk and encrypted
-        if let Ok(content) = fs::read_to_string(&path_str) {
-            // Drop the lock to avoid deadlock in encrypt_file
-            drop(files);
-
-            // Encrypt the file contents
-            if let Err(e) = SopsExtension::encrypt_file(&path_str, &content) {
-                eprintln!("Failed to encrypt SOPS file: {}", e);
-                return false;
-            }
-        } else {
+        // Drop the lock to avoid deadlock in encrypt_file
+        drop(files);
+
+        // Zed has already saved the plaintext to `path_str`; encrypt it in place.
+        if let Err(e) = encrypt_file(&path_str) {
+            eprintln!("Failed to encrypt SOPS file: {}", e);
             return false;
         }
+
+        // The file is ciphertext on disk again; clear its journal entry.
+        if let Err(e) = journal().clear(Path::new(&path_str)) {
+            eprintln!("Failed to clear journal entry for {}: {}", path_str, e);
+        }
+
+        // The on-disk ciphertext was just re-derived from the user's edits,
+        // so the pre-edit snapshot `open_document` stashed is now stale.
+        // Drop it (and the lock held for the decrypted-on-disk window) so a
+        // later `close_document` has nothing stale left to restore.
+        DECRYPTED_FILES.lock().unwrap().remove(&path_str);
+        HELD_LOCKS.lock().unwrap().remove(&path_str);
     }
 
     true
@@ -157,17 +153,22 @@ pub extern "C" fn close_document(path: *const c_char) -> bool {
     let files = DECRYPTED_FILES.lock().unwrap();
     if let Some(original_content) = files.get(&path_str) {
         // Restore the original encrypted content
-        if let Err(e) = fs::write(&path_str, original_content) {
+        if let Err(e) = atomic_write(Path::new(&path_str), original_content.as_bytes()) {
             eprintln!("Failed to restore encrypted content: {}", e);
             return false;
         }
+        if let Err(e) = journal().clear(Path::new(&path_str)) {
+            eprintln!("Failed to clear journal entry for {}: {}", path_str, e);
+        }
     }
 
     // Remove from our tracking
     let mut files = DECRYPTED_FILES.lock().unwrap();
     files.remove(&path_str);
+    drop(files);
+
+    // Release the advisory lock, letting other workers touch the file again.
+    HELD_LOCKS.lock().unwrap().remove(&path_str);
 
     true
 }
-
-zed::register_extension!(SopsExtension);